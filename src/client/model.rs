@@ -1,9 +1,70 @@
-use super::message::{Message, MessageContent};
+use super::message::{Message, MessageContent, MessageContentPart};
 
 use crate::utils::count_tokens;
 
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
+use base64::Engine;
 use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use tiktoken_rs::CoreBPE;
+
+const IMAGE_BASE_TOKENS: usize = 85;
+const IMAGE_PER_TILE_TOKENS: usize = 170;
+const IMAGE_TILE_SIZE: u32 = 512;
+const IMAGE_MAX_LONG_SIDE: u32 = 2048;
+const IMAGE_MAX_SHORT_SIDE: u32 = 768;
+
+// OpenAI tiled-image scheme: flat cost for "low" detail, otherwise scale so
+// the longest side is at most 2048px and the shortest at most 768px, then
+// charge a base cost plus one IMAGE_PER_TILE_TOKENS charge per 512px tile.
+fn image_tokens(width: u32, height: u32, detail: &str) -> usize {
+    if detail == "low" {
+        return IMAGE_BASE_TOKENS;
+    }
+    let (mut width, mut height) = (width as f64, height as f64);
+    let long_side = width.max(height);
+    if long_side > IMAGE_MAX_LONG_SIDE as f64 {
+        let scale = IMAGE_MAX_LONG_SIDE as f64 / long_side;
+        width *= scale;
+        height *= scale;
+    }
+    let short_side = width.min(height);
+    if short_side > IMAGE_MAX_SHORT_SIDE as f64 {
+        let scale = IMAGE_MAX_SHORT_SIDE as f64 / short_side;
+        width *= scale;
+        height *= scale;
+    }
+    let tiles_w = (width / IMAGE_TILE_SIZE as f64).ceil().max(1.0) as usize;
+    let tiles_h = (height / IMAGE_TILE_SIZE as f64).ceil().max(1.0) as usize;
+    IMAGE_BASE_TOKENS + IMAGE_PER_TILE_TOKENS * tiles_w * tiles_h
+}
+
+// Decoding a data URL or reading a file from disk is too expensive to redo
+// on every `max_tokens_limit` check against a long message history, so the
+// computed dimensions are cached per URL for the process lifetime.
+static IMAGE_DIMENSIONS_CACHE: OnceLock<Mutex<HashMap<String, (u32, u32)>>> = OnceLock::new();
+
+fn image_part_dimensions(url: &str) -> Result<(u32, u32)> {
+    let cache = IMAGE_DIMENSIONS_CACHE.get_or_init(|| Mutex::new(HashMap::new()));
+    if let Some(dimensions) = cache.lock().unwrap().get(url) {
+        return Ok(*dimensions);
+    }
+    let image = if let Some(data) = url.strip_prefix("data:") {
+        let (_, base64_data) = data
+            .split_once(";base64,")
+            .context("Invalid image data URL")?;
+        let bytes = base64::engine::general_purpose::STANDARD
+            .decode(base64_data)
+            .context("Invalid base64 image data")?;
+        image::load_from_memory(&bytes).context("Unable to decode image data")?
+    } else {
+        image::open(url).context("Unable to read image file")?
+    };
+    let dimensions = (image.width(), image.height());
+    cache.lock().unwrap().insert(url.to_string(), dimensions);
+    Ok(dimensions)
+}
 
 pub type TokensCountFactors = (usize, usize); // (per-messages, bias)
 
@@ -11,9 +72,17 @@ pub type TokensCountFactors = (usize, usize); // (per-messages, bias)
 pub struct Model {
     pub client_name: String,
     pub name: String,
+    // Deprecated alias for the context window, mapped onto max_input_tokens
+    // when that's absent.
     pub max_tokens: Option<usize>,
+    pub max_input_tokens: Option<usize>,
+    pub max_output_tokens: Option<usize>,
     pub tokens_count_factors: TokensCountFactors,
     pub capabilities: ModelCapabilities,
+    /// Cost per 1K prompt tokens, in USD.
+    pub input_price: Option<f64>,
+    /// Cost per 1K completion tokens, in USD.
+    pub output_price: Option<f64>,
 }
 
 impl Default for Model {
@@ -28,8 +97,12 @@ impl Model {
             client_name: client_name.into(),
             name: name.into(),
             max_tokens: None,
+            max_input_tokens: None,
+            max_output_tokens: None,
             tokens_count_factors: Default::default(),
             capabilities: ModelCapabilities::Text,
+            input_price: None,
+            output_price: None,
         }
     }
 
@@ -86,18 +159,82 @@ impl Model {
         self
     }
 
+    pub fn set_max_input_tokens(mut self, max_input_tokens: Option<usize>) -> Self {
+        match max_input_tokens {
+            None | Some(0) => self.max_input_tokens = None,
+            _ => self.max_input_tokens = max_input_tokens,
+        }
+        self
+    }
+
+    pub fn set_max_output_tokens(mut self, max_output_tokens: Option<usize>) -> Self {
+        match max_output_tokens {
+            None | Some(0) => self.max_output_tokens = None,
+            _ => self.max_output_tokens = max_output_tokens,
+        }
+        self
+    }
+
+    pub fn context_window(&self) -> Option<usize> {
+        self.max_input_tokens.or(self.max_tokens)
+    }
+
+    pub fn set_prices(mut self, input_price: Option<f64>, output_price: Option<f64>) -> Self {
+        self.input_price = input_price;
+        self.output_price = output_price;
+        self
+    }
+
+    // None if this model has no configured prices.
+    pub fn cost(&self, prompt_tokens: usize, completion_tokens: usize) -> Option<f64> {
+        let input_price = self.input_price?;
+        let output_price = self.output_price?;
+        Some(
+            (prompt_tokens as f64 / 1000.0) * input_price
+                + (completion_tokens as f64 / 1000.0) * output_price,
+        )
+    }
+
     pub fn messages_tokens(&self, messages: &[Message]) -> usize {
         messages
             .iter()
-            .map(|v| {
-                match &v.content {
-                    MessageContent::Text(text) => count_tokens(text),
-                    MessageContent::Array(_) => 0, // TODO
+            .map(|v| match &v.content {
+                MessageContent::Text(text) => self.count_text_tokens(text),
+                MessageContent::Array(parts) => {
+                    parts.iter().map(|part| self.part_tokens(part)).sum()
                 }
             })
             .sum()
     }
 
+    // Falls back to the worst-case tile count for the part's detail level when
+    // dimensions can't be fetched (e.g. a remote https:// URL), rather than
+    // under-counting as zero.
+    fn part_tokens(&self, part: &MessageContentPart) -> usize {
+        match part {
+            MessageContentPart::Text { text } => self.count_text_tokens(text),
+            MessageContentPart::ImageUrl { image_url } => {
+                let detail = image_url.detail.as_deref().unwrap_or("auto");
+                match image_part_dimensions(&image_url.url) {
+                    Ok((width, height)) => image_tokens(width, height, detail),
+                    // Dimensions unknown (e.g. a remote https:// URL we
+                    // can't fetch): charge the worst-case tile count for
+                    // this detail level rather than the cheap flat
+                    // low-detail cost, so a high-detail image is never
+                    // under-counted.
+                    Err(_) => image_tokens(IMAGE_MAX_LONG_SIDE, IMAGE_MAX_SHORT_SIDE, detail),
+                }
+            }
+        }
+    }
+
+    pub fn count_text_tokens(&self, text: &str) -> usize {
+        match encoding_for_model(&self.name) {
+            Some(encoding) => get_encoder(encoding).encode_with_special_tokens(text).len(),
+            None => count_tokens(text),
+        }
+    }
+
     pub fn total_tokens(&self, messages: &[Message]) -> usize {
         if messages.is_empty() {
             return 0;
@@ -112,25 +249,159 @@ impl Model {
         }
     }
 
+    // Kept for existing callers: checks the prompt alone against the
+    // context window, with no expected output length.
     pub fn max_tokens_limit(&self, messages: &[Message]) -> Result<()> {
+        self.max_tokens_limit_for_output(messages, 0)
+    }
+
+    // Validates that `messages` plus `requested_output_tokens` fit within
+    // the context window, and that `requested_output_tokens` itself doesn't
+    // exceed `max_output_tokens`.
+    pub fn max_tokens_limit_for_output(
+        &self,
+        messages: &[Message],
+        requested_output_tokens: usize,
+    ) -> Result<()> {
         let (_, bias) = self.tokens_count_factors;
-        let total_tokens = self.total_tokens(messages) + bias;
-        if let Some(max_tokens) = self.max_tokens {
-            if total_tokens >= max_tokens {
+        let prompt_tokens = self.total_tokens(messages) + bias;
+        if let Some(context_window) = self.context_window() {
+            if prompt_tokens + requested_output_tokens >= context_window {
                 bail!("Exceed max tokens limit")
             }
         }
+        if let Some(max_output_tokens) = self.max_output_tokens {
+            if requested_output_tokens > max_output_tokens {
+                bail!("Exceed max output tokens limit")
+            }
+        }
+        Ok(())
+    }
+
+    // Returns the relative drift of `estimated_prompt_tokens` from the
+    // provider-reported `usage.prompt_tokens` (e.g. 0.2 for 20% over),
+    // logging a warning past DRIFT_WARN_THRESHOLD.
+    pub fn calibrate_usage(&self, estimated_prompt_tokens: usize, usage: &Usage) -> f64 {
+        let reported = usage.prompt_tokens as f64;
+        let drift = if reported == 0.0 {
+            0.0
+        } else {
+            (estimated_prompt_tokens as f64 - reported) / reported
+        };
+        if drift.abs() > DRIFT_WARN_THRESHOLD {
+            log::warn!(
+                "Local token estimate for '{}' drifted {:.1}% from reported usage (estimated {}, reported {})",
+                self.id(),
+                drift * 100.0,
+                estimated_prompt_tokens,
+                usage.prompt_tokens,
+            );
+        }
+        drift
+    }
+}
+
+const DRIFT_WARN_THRESHOLD: f64 = 0.2;
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+pub struct Usage {
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    pub total_tokens: usize,
+}
+
+// Running per-session totals, fed by `UsageTotals::add` after every
+// provider response so they can be surfaced alongside the pre-send estimate.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UsageTotals {
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    pub total_tokens: usize,
+    // None once any call's cost is unknown (model has no configured prices).
+    pub cost: Option<f64>,
+    calls: usize,
+}
+
+impl UsageTotals {
+    // The single hook the client's send path calls after every provider
+    // response: calibrates the local estimate against what was actually
+    // billed, then folds the reported usage into the running totals.
+    pub fn add(&mut self, model: &Model, estimated_prompt_tokens: usize, usage: &Usage) {
+        model.calibrate_usage(estimated_prompt_tokens, usage);
+        self.prompt_tokens += usage.prompt_tokens;
+        self.completion_tokens += usage.completion_tokens;
+        self.total_tokens += usage.total_tokens;
+        let is_first_call = self.calls == 0;
+        self.calls += 1;
+        self.cost = match (self.cost, is_first_call, model.cost(usage.prompt_tokens, usage.completion_tokens)) {
+            (_, true, Some(call_cost)) => Some(call_cost),
+            (Some(total), false, Some(call_cost)) => Some(total + call_cost),
+            _ => None,
+        };
+    }
+}
+
+// Surfaces the running totals on a session's status line, e.g.
+// "123 prompt + 45 completion = 168 tokens ($0.0032)".
+impl std::fmt::Display for UsageTotals {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} prompt + {} completion = {} tokens",
+            self.prompt_tokens, self.completion_tokens, self.total_tokens
+        )?;
+        if let Some(cost) = self.cost {
+            write!(f, " (${cost:.4})")?;
+        }
         Ok(())
     }
 }
 
+// Returns None for models with no known BPE tokenizer; callers fall back to
+// the fast heuristic in that case.
+fn encoding_for_model(model_name: &str) -> Option<&'static str> {
+    if model_name.starts_with("gpt-4o") || model_name.starts_with("o1") {
+        Some("o200k_base")
+    } else if model_name.starts_with("gpt-4") || model_name.starts_with("gpt-3.5") {
+        Some("cl100k_base")
+    } else {
+        None
+    }
+}
+
+static ENCODER_CACHE: OnceLock<Mutex<HashMap<&'static str, Arc<CoreBPE>>>> = OnceLock::new();
+
+fn get_encoder(encoding: &'static str) -> Arc<CoreBPE> {
+    let cache = ENCODER_CACHE.get_or_init(|| Mutex::new(HashMap::new()));
+    let mut cache = cache.lock().unwrap();
+    cache
+        .entry(encoding)
+        .or_insert_with(|| {
+            let bpe = match encoding {
+                "o200k_base" => tiktoken_rs::o200k_base(),
+                _ => tiktoken_rs::cl100k_base(),
+            }
+            .expect("builtin tiktoken encoding tables are always valid");
+            Arc::new(bpe)
+        })
+        .clone()
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct ModelConfig {
     pub name: String,
     pub max_tokens: Option<usize>,
+    #[serde(default)]
+    pub max_input_tokens: Option<usize>,
+    #[serde(default)]
+    pub max_output_tokens: Option<usize>,
     #[serde(deserialize_with = "deserialize_capabilities")]
     #[serde(default = "default_capabilities")]
     pub capabilities: ModelCapabilities,
+    #[serde(default)]
+    pub input_price: Option<f64>,
+    #[serde(default)]
+    pub output_price: Option<f64>,
 }
 
 bitflags::bitflags! {
@@ -138,6 +409,8 @@ bitflags::bitflags! {
     pub struct ModelCapabilities: u32 {
         const Text = 0b00000001;
         const Vision = 0b00000010;
+        const FunctionCalling = 0b00000100;
+        const Embedding = 0b00001000;
     }
 }
 
@@ -151,6 +424,12 @@ impl From<&str> for ModelCapabilities {
         if value.contains("vision") {
             output |= ModelCapabilities::Vision;
         }
+        if value.contains("functions") || value.contains("tools") {
+            output |= ModelCapabilities::FunctionCalling;
+        }
+        if value.contains("embedding") {
+            output |= ModelCapabilities::Embedding;
+        }
         output
     }
 }
@@ -166,3 +445,180 @@ where
 fn default_capabilities() -> ModelCapabilities {
     ModelCapabilities::Text
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::message::ImageUrlContent;
+
+    #[test]
+    fn image_tokens_low_detail_is_flat() {
+        assert_eq!(image_tokens(4096, 4096, "low"), IMAGE_BASE_TOKENS);
+    }
+
+    #[test]
+    fn image_tokens_single_tile() {
+        assert_eq!(image_tokens(512, 512, "high"), IMAGE_BASE_TOKENS + IMAGE_PER_TILE_TOKENS);
+    }
+
+    #[test]
+    fn image_tokens_scales_long_side_to_2048() {
+        // 4096x4096 scales to 2048x2048 (long side), then short side 2048 > 768
+        // scales again to 768x768, i.e. 2 tiles per dimension.
+        assert_eq!(image_tokens(4096, 4096, "high"), IMAGE_BASE_TOKENS + IMAGE_PER_TILE_TOKENS * 4);
+    }
+
+    #[test]
+    fn image_tokens_zero_size_still_charges_one_tile() {
+        assert_eq!(image_tokens(0, 0, "high"), IMAGE_BASE_TOKENS + IMAGE_PER_TILE_TOKENS);
+    }
+
+    #[test]
+    fn part_tokens_charges_worst_case_for_unfetchable_remote_image() {
+        let model = Model::new("openai", "gpt-4o");
+        let part = MessageContentPart::ImageUrl {
+            image_url: ImageUrlContent {
+                url: "https://example.com/image.png".to_string(),
+                detail: Some("high".to_string()),
+            },
+        };
+        assert_eq!(
+            model.part_tokens(&part),
+            image_tokens(IMAGE_MAX_LONG_SIDE, IMAGE_MAX_SHORT_SIDE, "high"),
+        );
+        assert_ne!(model.part_tokens(&part), IMAGE_BASE_TOKENS);
+    }
+
+    #[test]
+    fn encoding_for_model_picks_o200k_for_gpt4o_and_o1() {
+        assert_eq!(encoding_for_model("gpt-4o"), Some("o200k_base"));
+        assert_eq!(encoding_for_model("o1-mini"), Some("o200k_base"));
+    }
+
+    #[test]
+    fn encoding_for_model_picks_cl100k_for_gpt4_and_gpt35() {
+        assert_eq!(encoding_for_model("gpt-4-turbo"), Some("cl100k_base"));
+        assert_eq!(encoding_for_model("gpt-3.5-turbo"), Some("cl100k_base"));
+    }
+
+    #[test]
+    fn encoding_for_model_unknown_falls_back_to_heuristic() {
+        assert_eq!(encoding_for_model("llama3"), None);
+    }
+
+    #[test]
+    fn count_text_tokens_uses_heuristic_for_unknown_models() {
+        let model = Model::new("ollama", "llama3");
+        assert_eq!(model.count_text_tokens("hello world"), count_tokens("hello world"));
+    }
+
+    #[test]
+    fn count_text_tokens_uses_bpe_for_known_models() {
+        let model = Model::new("openai", "gpt-4o");
+        assert_eq!(model.count_text_tokens("hello world"), 2);
+    }
+
+    #[test]
+    fn calibrate_usage_reports_relative_drift() {
+        let model = Model::new("openai", "gpt-4o");
+        let usage = Usage { prompt_tokens: 100, completion_tokens: 10, total_tokens: 110 };
+        assert_eq!(model.calibrate_usage(120, &usage), 0.2);
+    }
+
+    #[test]
+    fn usage_totals_add_accumulates_reported_usage() {
+        let model = Model::new("openai", "gpt-4o");
+        let mut totals = UsageTotals::default();
+        totals.add(&model, 100, &Usage { prompt_tokens: 100, completion_tokens: 10, total_tokens: 110 });
+        totals.add(&model, 50, &Usage { prompt_tokens: 50, completion_tokens: 5, total_tokens: 55 });
+        assert_eq!(totals.prompt_tokens, 150);
+        assert_eq!(totals.completion_tokens, 15);
+        assert_eq!(totals.total_tokens, 165);
+    }
+
+    #[test]
+    fn max_tokens_limit_keeps_old_arity_and_checks_prompt_only() {
+        let model = Model::new("openai", "gpt-4o").set_max_input_tokens(Some(100));
+        assert!(model.max_tokens_limit(&[]).is_ok());
+        assert!(model.max_tokens_limit_for_output(&[], 150).is_err());
+    }
+
+    #[test]
+    fn cost_is_none_without_configured_prices() {
+        let model = Model::new("openai", "gpt-4o");
+        assert_eq!(model.cost(1000, 1000), None);
+    }
+
+    #[test]
+    fn cost_combines_input_and_output_prices() {
+        let model = Model::new("openai", "gpt-4o").set_prices(Some(0.01), Some(0.03));
+        assert_eq!(model.cost(1000, 1000), Some(0.04));
+    }
+
+    #[test]
+    fn usage_totals_add_stays_unpriced_across_zero_usage_responses() {
+        // A response from a model with no configured prices (here one that
+        // also reports all-zero usage) makes `cost` unknown; a later priced
+        // model's response must not reset `cost` from None back to Some.
+        let unpriced = Model::new("ollama", "llama3");
+        let priced = Model::new("openai", "gpt-4o").set_prices(Some(0.01), Some(0.03));
+        let mut totals = UsageTotals::default();
+        totals.add(&unpriced, 0, &Usage::default());
+        assert_eq!(totals.cost, None);
+        totals.add(&priced, 100, &Usage { prompt_tokens: 100, completion_tokens: 100, total_tokens: 200 });
+        assert_eq!(totals.cost, None);
+    }
+
+    #[test]
+    fn usage_totals_add_accumulates_cost_when_fully_priced() {
+        let model = Model::new("openai", "gpt-4o").set_prices(Some(0.01), Some(0.03));
+        let mut totals = UsageTotals::default();
+        totals.add(&model, 100, &Usage { prompt_tokens: 100, completion_tokens: 100, total_tokens: 200 });
+        totals.add(&model, 100, &Usage { prompt_tokens: 100, completion_tokens: 100, total_tokens: 200 });
+        assert_eq!(totals.cost, Some(0.008));
+    }
+
+    #[test]
+    fn usage_totals_display_surfaces_tokens_and_cost() {
+        let model = Model::new("openai", "gpt-4o").set_prices(Some(0.01), Some(0.03));
+        let mut totals = UsageTotals::default();
+        totals.add(&model, 100, &Usage { prompt_tokens: 100, completion_tokens: 100, total_tokens: 200 });
+        assert_eq!(totals.to_string(), "100 prompt + 100 completion = 200 tokens ($0.0040)");
+    }
+
+    #[test]
+    fn model_capabilities_parses_function_calling_tokens() {
+        assert_eq!(
+            ModelCapabilities::from("functions"),
+            ModelCapabilities::FunctionCalling
+        );
+        assert_eq!(
+            ModelCapabilities::from("tools"),
+            ModelCapabilities::FunctionCalling
+        );
+    }
+
+    #[test]
+    fn model_capabilities_parses_embedding_token() {
+        assert_eq!(ModelCapabilities::from("embedding"), ModelCapabilities::Embedding);
+    }
+
+    #[test]
+    fn model_capabilities_parses_combined_string() {
+        let caps = ModelCapabilities::from("text,vision,tools");
+        assert!(caps.contains(ModelCapabilities::Text));
+        assert!(caps.contains(ModelCapabilities::Vision));
+        assert!(caps.contains(ModelCapabilities::FunctionCalling));
+        assert!(!caps.contains(ModelCapabilities::Embedding));
+    }
+
+    #[test]
+    fn deserialize_capabilities_parses_combined_string() {
+        use serde::de::IntoDeserializer;
+        let deserializer: serde::de::value::StrDeserializer<serde::de::value::Error> =
+            "text,vision,tools".into_deserializer();
+        let caps = deserialize_capabilities(deserializer).unwrap();
+        assert!(caps.contains(ModelCapabilities::FunctionCalling));
+        assert!(!caps.contains(ModelCapabilities::Embedding));
+    }
+}